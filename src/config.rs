@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serenity::all::GuildId;
+
+#[derive(Deserialize)]
+struct RawConfig {
+    guild: HashMap<String, RawGuildConfig>,
+}
+
+#[derive(Deserialize)]
+struct RawGuildConfig {
+    tmp_id: String,
+}
+
+/// Per-guild configuration, mapping each guild the bot is installed in to the
+/// `TruckersMP` VTC it should track.
+pub struct Config {
+    guilds: HashMap<GuildId, String>,
+}
+
+impl Config {
+    /// Load the per-guild TMP VTC mapping from a TOML file at `path`, e.g.:
+    ///
+    /// ```toml
+    /// [guild.123456789012345678]
+    /// tmp_id = "1234"
+    /// ```
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file at {path}"))?;
+        let raw: RawConfig = toml::from_str(&raw).context("failed to parse config file")?;
+
+        let mut guilds = HashMap::with_capacity(raw.guild.len());
+        for (guild_id, cfg) in raw.guild {
+            let guild_id: u64 = guild_id
+                .parse()
+                .with_context(|| format!("invalid guild id \"{guild_id}\" in config"))?;
+            guilds.insert(GuildId::new(guild_id), cfg.tmp_id);
+        }
+
+        Ok(Self { guilds })
+    }
+
+    /// The TMP VTC id configured for a guild, if any.
+    pub fn tmp_id_for(&self, guild_id: GuildId) -> Option<&str> {
+        self.guilds.get(&guild_id).map(String::as_str)
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, ScheduledEventId};
+
+use crate::tmp_response::EventIndex;
+
+/// A record of a Discord scheduled event the bot created for a `TruckersMP` event,
+/// keyed by guild and the `TruckersMP` `EventIndex` id.
+#[derive(Serialize, Deserialize)]
+struct StoredEvent {
+    discord_event_id: u64,
+    hash: u64,
+}
+
+/// Persistent mapping from (guild, `TruckersMP` event id) to the Discord scheduled
+/// event we created for it, along with a hash of the fields we care about so we
+/// can detect when an event needs to be re-synced. Keying by guild lets the same
+/// TMP event be tracked independently in multiple guilds.
+pub struct EventDb {
+    tree: sled::Db,
+    /// Tracks which (guild, event, lead time) reminders have already been sent,
+    /// so a restart doesn't double-post them.
+    reminders: sled::Tree,
+}
+
+impl EventDb {
+    /// Open (or create) the sled database at `path`.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let tree = sled::open(path).context("failed to open sled database")?;
+        let reminders = tree
+            .open_tree("reminders")
+            .context("failed to open reminders tree")?;
+        Ok(Self { tree, reminders })
+    }
+
+    /// Look up the Discord event id and stored hash for a `TruckersMP` event id.
+    pub fn get(
+        &self,
+        guild_id: GuildId,
+        tmp_id: u64,
+    ) -> anyhow::Result<Option<(ScheduledEventId, u64)>> {
+        let Some(bytes) = self.tree.get(key(guild_id, tmp_id))? else {
+            return Ok(None);
+        };
+        let stored: StoredEvent =
+            serde_json::from_slice(&bytes).context("failed to decode stored event")?;
+        Ok(Some((
+            ScheduledEventId::new(stored.discord_event_id),
+            stored.hash,
+        )))
+    }
+
+    /// Record (or update) the Discord event created for a `TruckersMP` event id.
+    pub fn set(
+        &self,
+        guild_id: GuildId,
+        tmp_id: u64,
+        discord_event_id: ScheduledEventId,
+        hash: u64,
+    ) -> anyhow::Result<()> {
+        let stored = StoredEvent {
+            discord_event_id: discord_event_id.get(),
+            hash,
+        };
+        let bytes = serde_json::to_vec(&stored).context("failed to encode stored event")?;
+        self.tree.insert(key(guild_id, tmp_id), bytes)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Remove the record for a `TruckersMP` event id, e.g. once it has been deleted
+    /// from Discord, along with any reminders recorded against it.
+    pub fn remove(&self, guild_id: GuildId, tmp_id: u64) -> anyhow::Result<()> {
+        self.tree.remove(key(guild_id, tmp_id))?;
+        self.tree.flush()?;
+
+        for entry in self.reminders.scan_prefix(reminder_prefix(guild_id, tmp_id)) {
+            let (key, _) = entry.context("failed to read entry from reminders tree")?;
+            self.reminders.remove(key)?;
+        }
+        self.reminders.flush()?;
+
+        Ok(())
+    }
+
+    /// Whether a reminder for this event at this lead time has already been sent.
+    pub fn reminder_sent(
+        &self,
+        guild_id: GuildId,
+        tmp_id: u64,
+        lead_secs: i64,
+    ) -> anyhow::Result<bool> {
+        Ok(self
+            .reminders
+            .contains_key(reminder_key(guild_id, tmp_id, lead_secs))?)
+    }
+
+    /// Record that a reminder for this event at this lead time has been sent.
+    pub fn mark_reminder_sent(
+        &self,
+        guild_id: GuildId,
+        tmp_id: u64,
+        lead_secs: i64,
+    ) -> anyhow::Result<()> {
+        self.reminders
+            .insert(reminder_key(guild_id, tmp_id, lead_secs), &[])?;
+        self.reminders.flush()?;
+        Ok(())
+    }
+
+    /// Iterate over the (`TruckersMP` event id, Discord scheduled event id) pairs
+    /// tracked for a single guild.
+    pub fn tracked_for_guild(
+        &self,
+        guild_id: GuildId,
+    ) -> impl Iterator<Item = anyhow::Result<(u64, ScheduledEventId)>> + '_ {
+        self.tree
+            .scan_prefix(guild_id.get().to_be_bytes())
+            .map(move |entry| {
+                let (key, value) = entry.context("failed to read entry from sled tree")?;
+                let tmp_id_bytes: [u8; 8] = key[8..]
+                    .try_into()
+                    .context("unexpected key length in sled tree")?;
+                let stored: StoredEvent =
+                    serde_json::from_slice(&value).context("failed to decode stored event")?;
+                Ok((
+                    u64::from_be_bytes(tmp_id_bytes),
+                    ScheduledEventId::new(stored.discord_event_id),
+                ))
+            })
+    }
+}
+
+/// Build the composite sled key for a (guild, `TruckersMP` event id) pair.
+fn key(guild_id: GuildId, tmp_id: u64) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&guild_id.get().to_be_bytes());
+    bytes[8..].copy_from_slice(&tmp_id.to_be_bytes());
+    bytes
+}
+
+/// Build the prefix shared by all reminder-tree keys for a (guild, `TruckersMP`
+/// event id) pair, regardless of lead time.
+fn reminder_prefix(guild_id: GuildId, tmp_id: u64) -> [u8; 16] {
+    key(guild_id, tmp_id)
+}
+
+/// Build the composite reminder-tree key for a (guild, `TruckersMP` event id, lead
+/// time) triple.
+fn reminder_key(guild_id: GuildId, tmp_id: u64, lead_secs: i64) -> [u8; 24] {
+    let mut bytes = [0u8; 24];
+    bytes[..16].copy_from_slice(&key(guild_id, tmp_id));
+    bytes[16..].copy_from_slice(&lead_secs.to_be_bytes());
+    bytes
+}
+
+/// Hash the fields of an event that, if changed, should trigger an edit of the
+/// Discord scheduled event.
+pub fn hash_event(event: &EventIndex) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    event.name().hash(&mut hasher);
+    event.start_at().hash(&mut hasher);
+    event.description().hash(&mut hasher);
+    hasher.finish()
+}
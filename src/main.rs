@@ -1,34 +1,122 @@
 #![warn(clippy::pedantic)]
 
+mod config;
+mod db;
+mod reminder;
 mod tmp_response;
 
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
-use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
-use chrono::Local;
+use chrono::DateTime;
+use chrono::LocalResult;
 use chrono::NaiveDateTime;
 use chrono::Utc;
+use chrono_tz::Tz;
+use config::Config;
+use db::EventDb;
 use regex::Regex;
+use serenity::all::ChannelId;
 use serenity::all::CreateAttachment;
 use serenity::all::CreateScheduledEvent;
+use serenity::all::EditScheduledEvent;
 use serenity::all::GuildId;
 use serenity::all::Ready;
 use serenity::all::ScheduledEventType;
 use serenity::all::Timestamp;
 use serenity::async_trait;
 use serenity::prelude::*;
+use tokio::sync::RwLock;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::FmtSubscriber;
 
-const TMP_BASE_URL: &str = "https://truckersmp.com";
+pub(crate) const TMP_BASE_URL: &str = "https://truckersmp.com";
 const EVENT_API_URL: &str = "https://api.truckersmp.com/v2/vtc/{id}/events";
 const EVENT_ATTENDING_API_URL: &str = "https://api.truckersmp.com/v2/vtc/{id}/events/attending";
 const MARKDOWN_IMAGE_REGEX: &str = r"!(\[[^\]]*\])?\([^)]*\)";
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 900;
+/// `TruckersMP` publishes event start times in UK local time.
+const DEFAULT_EVENT_TIMEZONE: Tz = Tz::Europe__London;
+const DEFAULT_EVENT_DURATION_SECS: u64 = 60 * 60;
+
+/// Fetch the created and attending events for a VTC from `TruckersMP`.
+async fn fetch_events(tmp_id: &str) -> anyhow::Result<Vec<tmp_response::EventIndex>> {
+    let data_created: tmp_response::Response = reqwest::get(EVENT_API_URL.replace("{id}", tmp_id))
+        .await?
+        .json()
+        .await?;
+    let data_attending: tmp_response::Response =
+        reqwest::get(EVENT_ATTENDING_API_URL.replace("{id}", tmp_id))
+            .await?
+            .json()
+            .await?;
+
+    if *data_created.error() || *data_attending.error() {
+        anyhow::bail!("TruckersMP returned an error response");
+    }
+
+    let mut data = data_created.response().clone();
+    let mut attending = data_attending.response().clone();
+    data.append(&mut attending);
+    tracing::info!("We have {} events from TruckersMP.", data.len());
+
+    Ok(data)
+}
+
+/// Parse a `TruckersMP` event's `start_at` field, interpreting it in `tz` (the
+/// timezone `TruckersMP` published it in) and converting to UTC. Returns `None`
+/// and logs a warning if the string is malformed or the resulting local time
+/// doesn't exist in `tz`, rather than guessing at a fallback instant.
+pub(crate) fn parse_start_time(event: &tmp_response::EventIndex, tz: Tz) -> Option<DateTime<Utc>> {
+    let naive = match NaiveDateTime::parse_from_str(event.start_at(), "%Y-%m-%d %H:%M:%S") {
+        Ok(naive) => naive,
+        Err(e) => {
+            tracing::warn!(
+                "Skipping event {}: failed to parse start time {:?}: {e}",
+                event.id(),
+                event.start_at(),
+            );
+            return None;
+        }
+    };
+
+    match naive.and_local_timezone(tz) {
+        LocalResult::Single(dt) => Some(dt.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earliest, _latest) => {
+            tracing::warn!(
+                "Start time for event {} is ambiguous in {tz}, using the earlier interpretation",
+                event.id(),
+            );
+            Some(earliest.with_timezone(&Utc))
+        }
+        LocalResult::None => {
+            tracing::warn!(
+                "Skipping event {}: start time {:?} does not exist in {tz}",
+                event.id(),
+                event.start_at(),
+            );
+            None
+        }
+    }
+}
 
 struct Handler {
-    data: Vec<tmp_response::EventIndex>,
+    config: Config,
+    /// Fetched events, keyed by TMP VTC id, shared across guilds tracking the
+    /// same VTC and swapped in wholesale on each poll tick.
+    data: Arc<RwLock<HashMap<String, Vec<tmp_response::EventIndex>>>>,
+    db: EventDb,
+    /// Channel reminders are posted to, and the lead times (in seconds) before
+    /// an event's start at which they fire. `None` if reminders aren't configured.
+    reminders: Option<(ChannelId, Vec<i64>)>,
+    /// Timezone `TruckersMP` publishes event start times in.
+    event_timezone: Tz,
+    /// Default event duration, used since `TruckersMP` doesn't publish an end time.
+    event_duration_secs: u64,
 }
 
 impl Handler {
@@ -36,78 +124,143 @@ impl Handler {
         &self,
         guild_id: &GuildId,
         ctx: &serenity::client::Context,
+        events: &[tmp_response::EventIndex],
     ) -> anyhow::Result<()> {
-        let events = guild_id
-            .scheduled_events(ctx.http(), false)
-            .await
-            .context("failed to get events")?;
-        let mut new_events = vec![];
-
-        // Figure out what events are new
-        'outer: for event in &self.data {
-            let event_id = *event.id();
-            for ev in &events {
-                if let Some(desc) = &ev.description {
-                    if desc.contains(&format!("### {event_id} ###")) {
-                        tracing::debug!("Event ID {event_id} already found, skipping...");
-                        continue 'outer;
-                    }
+        // SAFETY: The regex is checked externally
+        let re = Regex::new(MARKDOWN_IMAGE_REGEX).unwrap();
+        for event in events {
+            let tmp_id = *event.id();
+            let hash = db::hash_event(event);
+
+            let existing = self
+                .db
+                .get(*guild_id, tmp_id)
+                .context("failed to look up event in database")?;
+            if let Some((_, stored_hash)) = existing {
+                if stored_hash == hash {
+                    tracing::debug!("Event ID {tmp_id} unchanged, skipping...");
+                    continue;
                 }
             }
 
-            new_events.push(event.clone());
-        }
-
-        // Add new events
-        // SAFETY: The regex is checked externally
-        let re = Regex::new(MARKDOWN_IMAGE_REGEX).unwrap();
-        for event in new_events {
-            let start_time: NaiveDateTime =
-                NaiveDateTime::parse_from_str(event.start_at(), "%Y-%m-%d %H:%M:%S")
-                    .unwrap_or(Local::now().naive_local());
+            let Some(start_time) = parse_start_time(event, self.event_timezone) else {
+                continue;
+            };
 
-            if start_time.and_utc() <= Utc::now() {
+            if start_time <= Utc::now() {
                 // Skip if in the past
                 tracing::info!("Skipping event {} as it is in the past.", event.id());
                 continue;
             }
 
-            let end_time = start_time + Duration::from_secs(60 * 60);
-
-            let desc_prefix = format!("[See on TruckersMP]({}{})\n\n", TMP_BASE_URL, event.url(),);
+            let end_time = start_time + Duration::from_secs(self.event_duration_secs);
 
-            let desc_suffix = format!("\n\n### {} ###", event.id());
+            let desc_prefix = format!("[See on TruckersMP]({}{})\n\n", TMP_BASE_URL, event.url());
 
             let desc = event.description().clone().replace('\r', "");
             let mut desc = re.replace_all(&desc, "").to_string();
 
-            let mut truncate_at = 1000 - desc_prefix.len() - desc_suffix.len();
+            let mut truncate_at = 1000 - desc_prefix.len();
             while !desc.is_char_boundary(truncate_at) {
                 truncate_at -= 1;
             }
             desc.truncate(truncate_at);
 
-            let mut ev = CreateScheduledEvent::new(
-                ScheduledEventType::External,
-                event.name(),
-                Timestamp::from(start_time.and_utc()),
-            )
-            .description(format!("{desc_prefix}{desc}{desc_suffix}"))
-            .end_time(Timestamp::from(end_time.and_utc()))
-            .location(event.departure().city())
-            .audit_log_reason("Created from TruckersMP event");
-
-            if let Some(banner) = event.banner() {
-                if let Ok(img) = CreateAttachment::url(ctx.http(), banner).await {
-                    ev = ev.image(&img);
+            let full_desc = format!("{desc_prefix}{desc}");
+
+            let image = if let Some(banner) = event.banner() {
+                CreateAttachment::url(ctx.http(), banner).await.ok()
+            } else {
+                None
+            };
+
+            if let Some((discord_event_id, _)) = existing {
+                tracing::info!("Updating event for ID {tmp_id}");
+                let mut ev = EditScheduledEvent::new()
+                    .name(event.name())
+                    .description(full_desc.as_str())
+                    .start_time(Timestamp::from(start_time))
+                    .end_time(Timestamp::from(end_time))
+                    .location(event.departure().city())
+                    .audit_log_reason("Updated from TruckersMP event");
+
+                if let Some(image) = &image {
+                    ev = ev.image(image);
                 }
+
+                guild_id
+                    .edit_scheduled_event(ctx.http(), discord_event_id, ev)
+                    .await
+                    .context("Failed to edit existing event")?;
+
+                self.db
+                    .set(*guild_id, tmp_id, discord_event_id, hash)
+                    .context("failed to update event in database")?;
+            } else {
+                let mut ev = CreateScheduledEvent::new(
+                    ScheduledEventType::External,
+                    event.name(),
+                    Timestamp::from(start_time),
+                )
+                .description(full_desc.as_str())
+                .end_time(Timestamp::from(end_time))
+                .location(event.departure().city())
+                .audit_log_reason("Created from TruckersMP event");
+
+                if let Some(image) = &image {
+                    ev = ev.image(image);
+                }
+
+                tracing::info!("Creating event for ID {tmp_id}");
+                let created = guild_id
+                    .create_scheduled_event(&ctx, ev)
+                    .await
+                    .context("Failed to create new event")?;
+
+                self.db
+                    .set(*guild_id, tmp_id, created.id, hash)
+                    .context("failed to store new event in database")?;
             }
+        }
+
+        self.delete_removed_events(guild_id, ctx, events).await?;
+
+        Ok(())
+    }
 
-            tracing::info!("Creating event for ID {}", event.id());
-            guild_id
-                .create_scheduled_event(&ctx, ev)
+    /// Delete any Discord scheduled event the bot created for this guild whose
+    /// `TruckersMP` event no longer appears in the current fetch (i.e. it was
+    /// cancelled or rescheduled away). Only events tracked in the database are
+    /// touched, so manually-added guild events are never affected.
+    async fn delete_removed_events(
+        &self,
+        guild_id: &GuildId,
+        ctx: &serenity::client::Context,
+        events: &[tmp_response::EventIndex],
+    ) -> anyhow::Result<()> {
+        let current_ids: HashSet<u64> = events.iter().map(|event| *event.id()).collect();
+
+        for tracked in self.db.tracked_for_guild(*guild_id) {
+            let (tmp_id, discord_event_id) =
+                tracked.context("failed to read tracked event from database")?;
+
+            if current_ids.contains(&tmp_id) {
+                continue;
+            }
+
+            tracing::info!(
+                "TMP event {tmp_id} is no longer returned by TruckersMP, deleting Discord event"
+            );
+            // serenity 0.12 doesn't expose an audit-log reason for this endpoint
+            // (unlike create/edit, which take a builder with `.audit_log_reason`).
+            ctx.http()
+                .delete_scheduled_event(*guild_id, discord_event_id)
                 .await
-                .context("Failed to create new event")?;
+                .context("Failed to delete stale scheduled event")?;
+
+            self.db
+                .remove(*guild_id, tmp_id)
+                .context("failed to remove deleted event from database")?;
         }
 
         Ok(())
@@ -118,23 +271,72 @@ impl Handler {
 impl EventHandler for Handler {
     async fn ready(&self, ctx: serenity::client::Context, ready: Ready) {
         tracing::info!("{} is connected!", ready.user.name);
+        tracing::info!("Working across {} guild(s)", ready.guilds.len());
+
+        let poll_interval_secs = env::var("POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+        let mut interval = tokio::time::interval(Duration::from_secs(poll_interval_secs));
+
+        loop {
+            interval.tick().await;
+
+            // Fetch once per distinct TMP VTC id, so guilds sharing a VTC
+            // don't double-request the TruckersMP API.
+            let mut fresh = HashMap::new();
+            for guild in &ready.guilds {
+                let Some(tmp_id) = self.config.tmp_id_for(guild.id) else {
+                    tracing::warn!("No TMP VTC configured for guild {}, skipping", guild.id);
+                    continue;
+                };
+
+                if fresh.contains_key(tmp_id) {
+                    continue;
+                }
 
-        if ready.guilds.len() != 1 {
-            tracing::error!("This only functions with a bot in only one guild.");
-            process::exit(1);
-        }
-
-        // SAFETY: Length is checked prior
-        let guild = ready.guilds.first().unwrap();
-        let guild_id = guild.id;
-        tracing::info!("Working on guild: {guild_id}");
+                match fetch_events(tmp_id).await {
+                    Ok(events) => {
+                        fresh.insert(tmp_id.to_string(), events);
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to fetch events for TMP id {tmp_id}: {e:?}");
+                    }
+                }
+            }
+            *self.data.write().await = fresh;
+
+            let data = self.data.read().await;
+            for guild in &ready.guilds {
+                let Some(tmp_id) = self.config.tmp_id_for(guild.id) else {
+                    continue;
+                };
+                let Some(events) = data.get(tmp_id) else {
+                    continue;
+                };
+
+                tracing::info!("Processing events for guild {}", guild.id);
+                if let Err(e) = self.process_events(&guild.id, &ctx, events).await {
+                    tracing::error!("Failed to process events for guild {}: {e:?}", guild.id);
+                }
 
-        if let Err(e) = self.process_events(&guild_id, &ctx).await {
-            eprintln!("{e:?}");
-            process::exit(1);
+                if let Some((channel_id, lead_times_secs)) = &self.reminders {
+                    if let Err(e) = reminder::send_due_reminders(
+                        &ctx,
+                        &self.db,
+                        *channel_id,
+                        lead_times_secs,
+                        guild.id,
+                        events,
+                        self.event_timezone,
+                    )
+                    .await
+                    {
+                        tracing::error!("Failed to send reminders for guild {}: {e:?}", guild.id);
+                    }
+                }
+            }
         }
-
-        process::exit(0);
     }
 }
 
@@ -151,35 +353,48 @@ async fn main() -> anyhow::Result<()> {
 
     dotenvy::dotenv()?;
 
-    tracing::info!("Fetching events from TMP");
-
-    // Fetch events from TMP
-    let tmp_id = env::var("TMP_ID").context("Expected a TMP ID in the environment")?;
-    let data_created: tmp_response::Response = reqwest::get(EVENT_API_URL.replace("{id}", &tmp_id))
-        .await?
-        .json()
-        .await?;
-    let data_attending: tmp_response::Response =
-        reqwest::get(EVENT_ATTENDING_API_URL.replace("{id}", &tmp_id))
-            .await?
-            .json()
-            .await?;
-
-    if *data_created.error() || *data_attending.error() {
-        tracing::error!("Error in returned data!");
-        process::exit(1);
-    }
-
-    let mut data = data_created.response().clone();
-    {
-        // Append attending events
-        let mut d = data_attending.response().clone();
-        data.append(&mut d);
-    }
-    tracing::info!(
-        "We have {} events from TruckersMP.",
-        data_created.response().len() + data_attending.response().len()
-    );
+    // Load the per-guild VTC configuration
+    let config_path = env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".to_string());
+    let config = Config::load(&config_path).context("failed to load guild configuration")?;
+
+    // Open the event database
+    let db_path = env::var("DB_PATH").unwrap_or_else(|_| "db".to_string());
+    let db = EventDb::open(&db_path).context("failed to open event database")?;
+
+    // Configure reminders, if a channel is set
+    let reminders = match env::var("REMINDER_CHANNEL_ID") {
+        Ok(channel_id) => {
+            let channel_id: u64 = channel_id
+                .parse()
+                .context("REMINDER_CHANNEL_ID must be a Discord channel ID")?;
+            let lead_times_secs = match env::var("REMINDER_LEAD_TIMES_SECS") {
+                Ok(lead_times) => lead_times
+                    .split(',')
+                    .map(|secs| {
+                        secs.trim()
+                            .parse()
+                            .context("REMINDER_LEAD_TIMES_SECS must be a comma-separated list of seconds")
+                    })
+                    .collect::<anyhow::Result<Vec<i64>>>()?,
+                Err(_) => reminder::DEFAULT_LEAD_TIMES_SECS.to_vec(),
+            };
+            Some((ChannelId::new(channel_id), lead_times_secs))
+        }
+        Err(_) => None,
+    };
+
+    // Timezone TruckersMP publishes event start times in, and the default
+    // duration to give an event since TruckersMP doesn't publish an end time.
+    let event_timezone: Tz = match env::var("EVENT_TIMEZONE") {
+        Ok(tz) => tz
+            .parse()
+            .map_err(|e| anyhow::anyhow!("EVENT_TIMEZONE must be a valid IANA timezone name: {e}"))?,
+        Err(_) => DEFAULT_EVENT_TIMEZONE,
+    };
+    let event_duration_secs = env::var("DEFAULT_EVENT_DURATION_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EVENT_DURATION_SECS);
 
     // Login with a bot token from the environment
     tracing::info!("Connecting to Discord...");
@@ -187,7 +402,14 @@ async fn main() -> anyhow::Result<()> {
 
     // Create a new instance of the Client, logging in as a bot.
     let mut client = Client::builder(&token, GatewayIntents::empty())
-        .event_handler(Handler { data })
+        .event_handler(Handler {
+            config,
+            data: Arc::new(RwLock::new(HashMap::new())),
+            db,
+            reminders,
+            event_timezone,
+            event_duration_secs,
+        })
         .await
         .context("Error creating Discord client")?;
 
@@ -0,0 +1,76 @@
+use anyhow::Context;
+use chrono::{TimeDelta, Utc};
+use chrono_tz::Tz;
+use serenity::all::{CacheHttp, ChannelId, CreateMessage, GuildId};
+
+use crate::db::EventDb;
+use crate::tmp_response::EventIndex;
+use crate::{parse_start_time, TMP_BASE_URL};
+
+/// Default lead times (in seconds) before an event's start at which a reminder
+/// is posted: one hour and fifteen minutes out.
+pub const DEFAULT_LEAD_TIMES_SECS: &[i64] = &[3600, 900];
+
+/// Check each event against the configured lead times and post (and record as
+/// sent) any reminders that have newly come due.
+pub async fn send_due_reminders(
+    ctx: &serenity::client::Context,
+    db: &EventDb,
+    channel_id: ChannelId,
+    lead_times_secs: &[i64],
+    guild_id: GuildId,
+    events: &[EventIndex],
+    event_timezone: Tz,
+) -> anyhow::Result<()> {
+    let now = Utc::now();
+
+    for event in events {
+        let tmp_id = *event.id();
+        let Some(start_time) = parse_start_time(event, event_timezone) else {
+            continue;
+        };
+
+        for &lead_secs in lead_times_secs {
+            let fires_at = start_time - TimeDelta::seconds(lead_secs);
+
+            // This window is only guaranteed to be hit by some poll tick as long as
+            // `POLL_INTERVAL_SECS <= min(lead_times_secs)`; a larger poll interval
+            // could jump straight past `fires_at` to at-or-past `start_time` and
+            // silently drop the reminder.
+            if now < fires_at || now >= start_time {
+                continue;
+            }
+
+            if db.reminder_sent(guild_id, tmp_id, lead_secs)? {
+                continue;
+            }
+
+            let message = CreateMessage::new().content(format!(
+                "**{}** departs from {} in {}!\n{}{}",
+                event.name(),
+                event.departure().city(),
+                describe_lead(lead_secs),
+                TMP_BASE_URL,
+                event.url(),
+            ));
+
+            channel_id
+                .send_message(ctx.http(), message)
+                .await
+                .context("Failed to send event reminder")?;
+
+            db.mark_reminder_sent(guild_id, tmp_id, lead_secs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Render a lead time in seconds as a short human label, e.g. "1h" or "15m".
+fn describe_lead(lead_secs: i64) -> String {
+    if lead_secs % 3600 == 0 {
+        format!("{}h", lead_secs / 3600)
+    } else {
+        format!("{}m", lead_secs / 60)
+    }
+}